@@ -1,11 +1,31 @@
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::time::Duration;
 
-use rand::RngCore;
+use rand::{CryptoRng, RngCore};
 
 use crate::shared::ConnectionId;
 use crate::{crypto, MAX_CID_SIZE};
 
+/// The set of RNG capabilities required to drive CID and nonce generation
+///
+/// A blanket implementation covers every `RngCore + CryptoRng` type, so any cryptographically
+/// secure RNG (including one wrapping a vetted or hardware entropy source) can be plugged in. The
+/// `Send` bound keeps the stored override from downgrading the generator's own `Send`-ness.
+pub trait CidRng: RngCore + CryptoRng + Send {}
+impl<T: RngCore + CryptoRng + Send> CidRng for T {}
+
+/// Fill `dst` from `rng` if an override was supplied, otherwise from [`rand::thread_rng`]
+///
+/// `thread_rng` is `!Send`, so it can't be stored behind a `Send` trait object; the default path
+/// instead constructs it locally here, exactly as the baseline did per `generate_cid`.
+fn fill_random(rng: &mut Option<Box<dyn CidRng>>, dst: &mut [u8]) {
+    match rng {
+        Some(rng) => rng.fill_bytes(dst),
+        None => rand::thread_rng().fill_bytes(dst),
+    }
+}
+
 /// Generates connection IDs for incoming connections
 pub trait ConnectionIdGenerator: Send {
     /// Generates a new CID
@@ -39,10 +59,29 @@ pub struct InvalidCid;
 ///
 /// Random CIDs can be smaller than those produced by [`KeyedConnectionIdGenerator`], but cannot be
 /// usefully [`validate`](ConnectionIdGenerator::validate)d.
-#[derive(Debug, Clone, Copy)]
+///
+/// Note: holding a boxed RNG, this type is no longer `Copy`/`Clone` (it was in earlier releases).
+/// This is a semver-breaking change; pass it by value or behind a reference instead of copying.
+///
+/// Only a single fixed `cid_len` per endpoint is supported. A variable-length ("greased") mode
+/// that varies the CID length per connection is deferred: because short-header packets don't carry
+/// the DCID length, the endpoint demux parses incoming DCIDs with one global [`cid_len`], so a
+/// per-connection length would have to be threaded through endpoint/connection CID state first.
+///
+/// [`cid_len`]: ConnectionIdGenerator::cid_len
 pub struct RandomConnectionIdGenerator {
     cid_len: usize,
     lifetime: Option<Duration>,
+    rng: Option<Box<dyn CidRng>>,
+}
+
+impl std::fmt::Debug for RandomConnectionIdGenerator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RandomConnectionIdGenerator")
+            .field("cid_len", &self.cid_len)
+            .field("lifetime", &self.lifetime)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Default for RandomConnectionIdGenerator {
@@ -50,6 +89,7 @@ impl Default for RandomConnectionIdGenerator {
         Self {
             cid_len: 8,
             lifetime: None,
+            rng: None,
         }
     }
 }
@@ -71,12 +111,21 @@ impl RandomConnectionIdGenerator {
         self.lifetime = Some(d);
         self
     }
+
+    /// Drive CID generation from a caller-supplied cryptographically secure RNG
+    ///
+    /// Defaults to [`rand::thread_rng`]. Supplying a seeded RNG enables reproducible tests and
+    /// deterministic fuzzing.
+    pub fn with_rng(mut self, rng: impl RngCore + CryptoRng + Send + 'static) -> Self {
+        self.rng = Some(Box::new(rng));
+        self
+    }
 }
 
 impl ConnectionIdGenerator for RandomConnectionIdGenerator {
     fn generate_cid(&mut self) -> ConnectionId {
         let mut bytes_arr = [0; MAX_CID_SIZE];
-        rand::thread_rng().fill_bytes(&mut bytes_arr[..self.cid_len]);
+        fill_random(&mut self.rng, &mut bytes_arr[..self.cid_len]);
 
         ConnectionId::new(&bytes_arr[..self.cid_len])
     }
@@ -96,6 +145,10 @@ impl ConnectionIdGenerator for RandomConnectionIdGenerator {
 pub struct KeyedConnectionIdGenerator {
     key: Arc<dyn crypto::HmacKey>,
     lifetime: Option<Duration>,
+    rng: Option<Box<dyn CidRng>>,
+    /// Payload encrypted into every CID, recoverable by a cooperating component via
+    /// [`recover`](Self::recover)
+    payload: Option<Box<[u8]>>,
 }
 
 impl KeyedConnectionIdGenerator {
@@ -115,9 +168,16 @@ impl KeyedConnectionIdGenerator {
             key.signature_len() < MAX_SIGNATURE_LEN,
             "key must generate at most a 128 byte signature"
         );
+        assert!(
+            key.signature_len() >= RESET_TOKEN_LEN,
+            "key must generate at least a {RESET_TOKEN_LEN} byte signature, otherwise a derived \
+             stateless reset token would contain predictable un-signed zero bytes"
+        );
         Self {
             key,
             lifetime: None,
+            rng: None,
+            payload: None,
         }
     }
 
@@ -126,6 +186,103 @@ impl KeyedConnectionIdGenerator {
         self.lifetime = Some(d);
         self
     }
+
+    /// Drive nonce generation from a caller-supplied cryptographically secure RNG
+    ///
+    /// Defaults to [`rand::thread_rng`]. Supplying a seeded RNG enables reproducible tests and
+    /// deterministic fuzzing.
+    pub fn with_rng(mut self, rng: impl RngCore + CryptoRng + Send + 'static) -> Self {
+        self.rng = Some(Box::new(rng));
+        self
+    }
+
+    /// Embed a fixed-size routable `payload` (e.g. a server or shard identifier) into every CID
+    ///
+    /// The payload is encrypted under the HMAC key and authenticated together with the rest of the
+    /// CID, so it stays opaque to external observers yet can be recovered by a cooperating
+    /// component via [`recover`](Self::recover) — giving operators stateless routing without a
+    /// shared lookup table. `payload` must be at most [`MAX_ROUTING_PAYLOAD_LEN`] bytes and no
+    /// longer than the HMAC key's signature length, which bounds the derived keystream.
+    ///
+    /// An operator reaches this through the [`ConnectionIdGenerator`] they install on the endpoint;
+    /// a cooperating front end recovers the value with [`recover`](Self::recover).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `payload` exceeds [`MAX_ROUTING_PAYLOAD_LEN`] or the key's signature length. Like
+    /// [`from_key`](Self::from_key)'s key-length check, these bound a static configuration value
+    /// set once at startup, not per-connection input, so a panic surfaces the misconfiguration
+    /// immediately rather than silently weakening every CID.
+    pub fn with_payload(mut self, payload: &[u8]) -> Self {
+        assert!(
+            payload.len() <= MAX_ROUTING_PAYLOAD_LEN,
+            "routing payload must be at most {MAX_ROUTING_PAYLOAD_LEN} bytes"
+        );
+        assert!(
+            payload.len() <= self.key.signature_len(),
+            "routing payload must not exceed the HMAC key's signature length, \
+             otherwise the keystream would be zero-padded and leak payload bytes in cleartext"
+        );
+        self.payload = Some(payload.into());
+        self
+    }
+
+    /// Recover the payload embedded by [`with_payload`](Self::with_payload)
+    ///
+    /// Returns `None` if this generator carries no payload, if `cid` has an unexpected length, or
+    /// if its authentication tag does not verify under the HMAC key.
+    pub fn recover(&self, cid: &ConnectionId) -> Option<Vec<u8>> {
+        let payload_len = self.payload.as_ref()?.len();
+        if cid.len() != NONCE_LEN + payload_len + SIGNATURE_LEN {
+            return None;
+        }
+        let nonce = &cid[..NONCE_LEN];
+        let ciphertext = &cid[NONCE_LEN..NONCE_LEN + payload_len];
+        let signature = &cid[NONCE_LEN + payload_len..];
+
+        let expected = self.mac(TAG_DOMAIN, nonce, ciphertext);
+        if expected[..SIGNATURE_LEN] != signature[..] {
+            return None;
+        }
+
+        let keystream = self.mac(KEYSTREAM_DOMAIN, nonce, &[]);
+        Some(
+            ciphertext
+                .iter()
+                .zip(&keystream[..payload_len])
+                .map(|(c, k)| c ^ k)
+                .collect(),
+        )
+    }
+
+    /// Derive the stateless reset token for `cid` deterministically from the HMAC key
+    ///
+    /// Computed as an HMAC under the generator's key over a domain-separation byte followed by
+    /// `cid`, so a restarted or load-balanced endpoint can emit a correct stateless reset for a
+    /// connection it no longer holds state for, purely from the CID and the shared key.
+    pub fn reset_token(&self, cid: &ConnectionId) -> [u8; RESET_TOKEN_LEN] {
+        let mut input = [0u8; 1 + MAX_CID_SIZE];
+        input[0] = RESET_TOKEN_DOMAIN;
+        input[1..1 + cid.len()].copy_from_slice(cid);
+        let mut out = [0u8; MAX_SIGNATURE_LEN];
+        self.key
+            .sign(&input[..1 + cid.len()], &mut out[..self.key.signature_len()]);
+        let mut token = [0u8; RESET_TOKEN_LEN];
+        token.copy_from_slice(&out[..RESET_TOKEN_LEN]);
+        token
+    }
+
+    /// HMAC over a domain-separation byte followed by `a` and `b`
+    fn mac(&self, domain: u8, a: &[u8], b: &[u8]) -> [u8; MAX_SIGNATURE_LEN] {
+        let mut input = [0u8; 1 + NONCE_LEN + MAX_ROUTING_PAYLOAD_LEN];
+        input[0] = domain;
+        input[1..1 + a.len()].copy_from_slice(a);
+        input[1 + a.len()..1 + a.len() + b.len()].copy_from_slice(b);
+        let mut out = [0u8; MAX_SIGNATURE_LEN];
+        self.key
+            .sign(&input[..1 + a.len() + b.len()], &mut out[..self.key.signature_len()]);
+        out
+    }
 }
 
 #[cfg(feature = "ring")]
@@ -137,16 +294,41 @@ impl Default for KeyedConnectionIdGenerator {
 
 impl ConnectionIdGenerator for KeyedConnectionIdGenerator {
     fn generate_cid(&mut self) -> ConnectionId {
-        let mut bytes_arr = [0; NONCE_LEN + MAX_SIGNATURE_LEN];
-        rand::thread_rng().fill_bytes(&mut bytes_arr[..NONCE_LEN]);
-        let (nonce, signature) = bytes_arr.split_at_mut(NONCE_LEN);
-        self.key
-            .sign(nonce, &mut signature[..self.key.signature_len()]);
-        ConnectionId::new(&bytes_arr[..self.cid_len()])
+        let payload_len = match self.payload.as_ref() {
+            Some(payload) => payload.len(),
+            None => {
+                // Plain keyed CID: nonce || HMAC(nonce)
+                let mut bytes_arr = [0; NONCE_LEN + MAX_SIGNATURE_LEN];
+                fill_random(&mut self.rng, &mut bytes_arr[..NONCE_LEN]);
+                let (nonce, signature) = bytes_arr.split_at_mut(NONCE_LEN);
+                self.key
+                    .sign(nonce, &mut signature[..self.key.signature_len()]);
+                return ConnectionId::new(&bytes_arr[..NONCE_LEN + SIGNATURE_LEN]);
+            }
+        };
+
+        // Routed CID: nonce || (payload ^ keystream) || HMAC(nonce || ciphertext)
+        let mut bytes_arr = [0; NONCE_LEN + MAX_ROUTING_PAYLOAD_LEN + MAX_SIGNATURE_LEN];
+        fill_random(&mut self.rng, &mut bytes_arr[..NONCE_LEN]);
+
+        let keystream = self.mac(KEYSTREAM_DOMAIN, &bytes_arr[..NONCE_LEN], &[]);
+        for (i, byte) in self.payload.as_ref().expect("payload present").iter().enumerate() {
+            bytes_arr[NONCE_LEN + i] = byte ^ keystream[i];
+        }
+
+        let tag = self.mac(
+            TAG_DOMAIN,
+            &bytes_arr[..NONCE_LEN],
+            &bytes_arr[NONCE_LEN..NONCE_LEN + payload_len],
+        );
+        bytes_arr[NONCE_LEN + payload_len..NONCE_LEN + payload_len + SIGNATURE_LEN]
+            .copy_from_slice(&tag[..SIGNATURE_LEN]);
+
+        ConnectionId::new(&bytes_arr[..NONCE_LEN + payload_len + SIGNATURE_LEN])
     }
 
     fn cid_len(&self) -> usize {
-        NONCE_LEN + SIGNATURE_LEN
+        NONCE_LEN + self.payload.as_ref().map_or(0, |p| p.len()) + SIGNATURE_LEN
     }
 
     fn cid_lifetime(&self) -> Option<Duration> {
@@ -154,6 +336,22 @@ impl ConnectionIdGenerator for KeyedConnectionIdGenerator {
     }
 
     fn validate(&self, cid: &ConnectionId) -> Result<(), InvalidCid> {
+        if let Some(payload) = self.payload.as_ref() {
+            // Authenticate nonce || ciphertext for routed CIDs
+            let payload_len = payload.len();
+            if cid.len() != NONCE_LEN + payload_len + SIGNATURE_LEN {
+                return Err(InvalidCid);
+            }
+            let expected = self.mac(
+                TAG_DOMAIN,
+                &cid[..NONCE_LEN],
+                &cid[NONCE_LEN..NONCE_LEN + payload_len],
+            );
+            return (expected[..SIGNATURE_LEN] == cid[NONCE_LEN + payload_len..])
+                .then_some(())
+                .ok_or(InvalidCid);
+        }
+
         let (nonce, signature) = cid.split_at(NONCE_LEN);
         let mut expected_signature = [0; MAX_SIGNATURE_LEN];
         self.key
@@ -164,10 +362,84 @@ impl ConnectionIdGenerator for KeyedConnectionIdGenerator {
     }
 }
 
+/// Bounded set of retired / pending-retirement CID sequence numbers
+///
+/// A peer that rapidly forces CID retirement (e.g. via `RETIRE_CONNECTION_ID` floods or
+/// lifetime-driven rotation, see [`ConnectionIdGenerator::cid_lifetime`]) can grow server-side
+/// state without bound — the same class of issue as CVE-2024-1410. This type caps that state: its
+/// capacity is derived as [`RETIRED_CID_LIMIT_MULTIPLIER`] times the negotiated
+/// `active_connection_id_limit`, and [`insert`](Self::insert) beyond it returns
+/// [`ConnectionIdsExhausted`] rather than allocating unboundedly.
+///
+/// To actually mitigate the DoS, the connection's CID state must own one of these and call
+/// [`insert`](Self::insert) for every retired sequence number, mapping [`ConnectionIdsExhausted`]
+/// to a `CONNECTION_ID_LIMIT_ERROR` transport error.
+///
+/// No code in this crate constructs a `RetiredCids` or calls [`insert`](Self::insert): this type
+/// supplies the bound, but the retirement path does not use it, so on its own it does **not**
+/// close the CVE-2024-1410 class of issue.
+#[derive(Debug)]
+pub struct RetiredCids {
+    seqs: HashSet<u64>,
+    capacity: usize,
+}
+
+impl RetiredCids {
+    /// Create a set bounded relative to the peer's `active_connection_id_limit`
+    pub fn new(active_connection_id_limit: u64) -> Self {
+        let capacity = active_connection_id_limit
+            .saturating_mul(RETIRED_CID_LIMIT_MULTIPLIER)
+            .try_into()
+            .unwrap_or(usize::MAX);
+        Self {
+            seqs: HashSet::new(),
+            capacity,
+        }
+    }
+
+    /// Record `seq` as retired
+    ///
+    /// Returns `Ok(true)` if the sequence number was newly inserted and `Ok(false)` if it was
+    /// already present. Inserting a new sequence number once the set is at capacity yields
+    /// [`ConnectionIdsExhausted`]; mapping that to a transport error is left to the caller.
+    pub fn insert(&mut self, seq: u64) -> Result<bool, ConnectionIdsExhausted> {
+        if !self.seqs.contains(&seq) && self.seqs.len() >= self.capacity {
+            return Err(ConnectionIdsExhausted);
+        }
+        Ok(self.seqs.insert(seq))
+    }
+
+    /// Whether `seq` has been retired
+    pub fn contains(&self, seq: u64) -> bool {
+        self.seqs.contains(&seq)
+    }
+}
+
+/// Multiplier applied to `active_connection_id_limit` to bound retired-CID bookkeeping
+const RETIRED_CID_LIMIT_MULTIPLIER: u64 = 3;
+
+/// Too many connection IDs have been retired to track within the negotiated bound
+#[derive(Debug, Copy, Clone)]
+pub struct ConnectionIdsExhausted;
+
 const NONCE_LEN: usize = 3; // Good for more than 16 million connections
 const SIGNATURE_LEN: usize = 5; // 8-byte total CID length
 const MAX_SIGNATURE_LEN: usize = 128;
 
+/// Largest routable payload [`KeyedConnectionIdGenerator::with_payload`] will embed
+///
+/// Bounded so the total CID length stays within `MAX_CID_SIZE`.
+pub const MAX_ROUTING_PAYLOAD_LEN: usize = MAX_CID_SIZE - NONCE_LEN - SIGNATURE_LEN;
+
+/// Domain-separation byte for the payload keystream derivation
+const KEYSTREAM_DOMAIN: u8 = 0x00;
+/// Domain-separation byte for the CID authentication tag
+const TAG_DOMAIN: u8 = 0x01;
+/// Domain-separation byte for stateless-reset-token derivation
+const RESET_TOKEN_DOMAIN: u8 = 0x02;
+/// Length of a QUIC stateless reset token
+const RESET_TOKEN_LEN: usize = 16;
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -179,4 +451,51 @@ mod tests {
         let cid = generator.generate_cid();
         generator.validate(&cid).unwrap();
     }
+
+    #[test]
+    #[cfg(feature = "ring")]
+    fn routed_cid_round_trips() {
+        let mut generator = KeyedConnectionIdGenerator::new().with_payload(&[0xDE, 0xAD, 0xBE, 0xEF]);
+        let cid = generator.generate_cid();
+        generator.validate(&cid).unwrap();
+        assert_eq!(generator.recover(&cid).as_deref(), Some(&[0xDE, 0xAD, 0xBE, 0xEF][..]));
+    }
+
+    #[test]
+    #[cfg(feature = "ring")]
+    fn reset_token_is_deterministic() {
+        let mut generator = KeyedConnectionIdGenerator::new();
+        let cid = generator.generate_cid();
+        let other = generator.generate_cid();
+        assert_eq!(generator.reset_token(&cid), generator.reset_token(&cid));
+        assert_ne!(generator.reset_token(&cid), generator.reset_token(&other));
+    }
+
+    #[test]
+    fn retired_cids_are_bounded() {
+        let mut retired = RetiredCids::new(4); // capacity 12
+        for seq in 0..12 {
+            assert!(retired.insert(seq).unwrap());
+        }
+        // Re-inserting an existing sequence number stays within the bound
+        assert!(!retired.insert(0).unwrap());
+        // A novel sequence number past the bound is rejected
+        assert!(retired.insert(12).is_err());
+        assert!(retired.contains(11));
+    }
+
+    #[test]
+    fn supplied_rng_is_reproducible() {
+        use rand::SeedableRng;
+
+        let cid_of = |seed| {
+            let rng = rand::rngs::StdRng::seed_from_u64(seed);
+            RandomConnectionIdGenerator::new(8)
+                .with_rng(rng)
+                .generate_cid()
+        };
+        assert_eq!(cid_of(42), cid_of(42));
+        assert_ne!(cid_of(42), cid_of(43));
+    }
+
 }